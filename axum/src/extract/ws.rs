@@ -0,0 +1,1260 @@
+//! Handle WebSocket connections.
+//!
+//! # Example
+//!
+//! ```
+//! use axum::{
+//!     extract::ws::{WebSocketUpgrade, WebSocket},
+//!     routing::get,
+//!     response::Response,
+//!     Router,
+//! };
+//!
+//! let app = Router::new().route("/ws", get(handler));
+//!
+//! async fn handler(ws: WebSocketUpgrade) -> Response {
+//!     ws.on_upgrade(handle_socket)
+//! }
+//!
+//! async fn handle_socket(mut socket: WebSocket) {
+//!     while let Some(msg) = socket.recv().await {
+//!         let msg = if let Ok(msg) = msg {
+//!             msg
+//!         } else {
+//!             // client disconnected
+//!             return;
+//!         };
+//!
+//!         if socket.send(msg).await.is_err() {
+//!             // client disconnected
+//!             return;
+//!         }
+//!     }
+//! }
+//! ```
+
+use self::rejection::*;
+use super::FromRequestParts;
+use crate::{
+    body::Body,
+    response::Response,
+    Error,
+};
+use async_trait::async_trait;
+use futures_util::{
+    sink::{Sink, SinkExt},
+    stream::{Stream, StreamExt},
+};
+use http::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    request::Parts,
+    Method, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use sha1::{Digest, Sha1};
+use std::{
+    borrow::Cow,
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::{
+    tungstenite::{
+        self as ts,
+        protocol::{self, WebSocketConfig},
+    },
+    WebSocketStream,
+};
+
+/// Extractor for establishing WebSocket connections.
+///
+/// See the [module docs](self) for an example.
+pub struct WebSocketUpgrade<F = DefaultOnFailedUpgrade> {
+    config: WebSocketConfig,
+    /// The chosen protocol sent in the `Sec-WebSocket-Protocol` header of the response.
+    protocol: Option<HeaderValue>,
+    /// The client's `Sec-WebSocket-Key` header; extraction fails if it is not present.
+    sec_websocket_key: HeaderValue,
+    on_upgrade: hyper::upgrade::OnUpgrade,
+    on_failed_upgrade: F,
+    keep_alive: Option<KeepAliveConfig>,
+    compression: Option<DeflateConfig>,
+    sec_websocket_protocol: Option<HeaderValue>,
+    /// The raw `Sec-WebSocket-Extensions` offer from the client, answered during the upgrade.
+    sec_websocket_extensions: Option<HeaderValue>,
+}
+
+impl<F> std::fmt::Debug for WebSocketUpgrade<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketUpgrade")
+            .field("config", &self.config)
+            .field("protocol", &self.protocol)
+            .field("sec_websocket_key", &self.sec_websocket_key)
+            .field("sec_websocket_protocol", &self.sec_websocket_protocol)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F> WebSocketUpgrade<F> {
+    /// The target minimum size of the write buffer to reach before writing the data to the
+    /// underlying stream.
+    ///
+    /// See [`tungstenite::protocol::WebSocketConfig::write_buffer_size`] for more details.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.config.write_buffer_size = size;
+        self
+    }
+
+    /// The max size of the write buffer in bytes.
+    ///
+    /// See [`tungstenite::protocol::WebSocketConfig::max_write_buffer_size`] for more details.
+    pub fn max_write_buffer_size(mut self, max: usize) -> Self {
+        self.config.max_write_buffer_size = max;
+        self
+    }
+
+    /// Set the maximum message size (defaults to 64 MiB).
+    pub fn max_message_size(mut self, max: usize) -> Self {
+        self.config.max_message_size = Some(max);
+        self
+    }
+
+    /// Set the maximum frame size (defaults to 16 MiB).
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.config.max_frame_size = Some(max);
+        self
+    }
+
+    /// Enable a transparent heartbeat on the connection.
+    ///
+    /// Once configured the upgraded [`WebSocket`] sends a [`Message::Ping`] every
+    /// [`interval`](KeepAliveConfig::interval) and resets a deadline each time any
+    /// [`Message::Pong`] comes back. If [`timeout`](KeepAliveConfig::timeout) elapses with no
+    /// pong the socket is closed with code `1000` and the next `recv`/`next` yields `None`.
+    ///
+    /// The ping/pong bookkeeping is interleaved with the user's own sends by wrapping the
+    /// inner sink and stream, so handlers never have to observe the control frames — though
+    /// they still pass through to [`recv`](WebSocket::recv) for anyone who wants to see them.
+    ///
+    /// <div class="warning">
+    ///
+    /// The heartbeat is driven from the socket's own poll paths rather than an independent
+    /// task: it only advances while the handler is polling the socket for a read
+    /// ([`recv`](WebSocket::recv)/[`next`](futures_util::StreamExt::next)) or a write
+    /// ([`send`](WebSocket::send)/flush). A handler that parks indefinitely without touching
+    /// the socket will not emit pings or trip the pong timeout until it next polls. This avoids
+    /// spawning a task per connection, matching how the rest of the module
+    /// threads state through the `Stream`/`Sink` impls.
+    ///
+    /// </div>
+    pub fn keep_alive(mut self, config: KeepAliveConfig) -> Self {
+        self.keep_alive = Some(config);
+        self
+    }
+
+    /// Offer per-message compression (permessage-deflate, RFC 7692) on the handshake.
+    ///
+    /// The `*_max_window_bits` cap the LZ77 window each side keeps and the
+    /// `*_no_context_takeover` flags decide whether the flate dictionary is carried between
+    /// messages or reset after each one. The [`Message`] API the handler sees stays
+    /// uncompressed — only the wire bytes would change.
+    ///
+    /// <div class="warning">
+    ///
+    /// This is currently a no-op: mainline tokio-tungstenite does not support permessage-deflate
+    /// ([tokio-tungstenite#21]), so the extension is **not** answered on the handshake and the
+    /// connection stays uncompressed. The negotiation and deflate codec are implemented and
+    /// unit-tested behind this builder, ready to be wired in once the upstream library can
+    /// carry compressed frames. Answering the extension without that support would set no RSV1
+    /// bit and fail UTF-8 validation on inbound text, producing a broken connection.
+    ///
+    /// [tokio-tungstenite#21]: https://github.com/snapview/tokio-tungstenite/issues/21
+    ///
+    /// </div>
+    pub fn compression(mut self, config: DeflateConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Set the known protocols.
+    ///
+    /// If the protocol sent by the client is in this list, it is returned in the response.
+    pub fn protocols<I>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Cow<'static, str>>,
+    {
+        if let Some(req_protocols) = self
+            .sec_websocket_protocol
+            .as_ref()
+            .and_then(|p| p.to_str().ok())
+        {
+            self.protocol = protocols
+                .into_iter()
+                .map(Into::into)
+                .find(|protocol| {
+                    req_protocols
+                        .split(',')
+                        .any(|req_protocol| req_protocol.trim() == protocol)
+                })
+                .map(|protocol| match protocol {
+                    Cow::Owned(s) => HeaderValue::from_str(&s).unwrap(),
+                    Cow::Borrowed(s) => HeaderValue::from_static(s),
+                });
+        }
+
+        self
+    }
+
+    /// Provide a callback to call if upgrading the connection fails.
+    pub fn on_failed_upgrade<C>(self, callback: C) -> WebSocketUpgrade<C>
+    where
+        C: OnFailedUpgrade,
+    {
+        WebSocketUpgrade {
+            config: self.config,
+            protocol: self.protocol,
+            sec_websocket_key: self.sec_websocket_key,
+            on_upgrade: self.on_upgrade,
+            on_failed_upgrade: callback,
+            keep_alive: self.keep_alive,
+            compression: self.compression,
+            sec_websocket_protocol: self.sec_websocket_protocol,
+            sec_websocket_extensions: self.sec_websocket_extensions,
+        }
+    }
+
+    /// Finalize upgrading the connection and call the provided callback with the stream.
+    pub fn on_upgrade<C, Fut>(self, callback: C) -> Response
+    where
+        C: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+        F: OnFailedUpgrade,
+    {
+        let on_upgrade = self.on_upgrade;
+        let config = self.config;
+        let on_failed_upgrade = self.on_failed_upgrade;
+        let keep_alive = self.keep_alive;
+
+        // permessage-deflate negotiation ([`negotiate_deflate`]) and the [`Deflate`] codec are
+        // implemented and unit-tested, but we deliberately do not answer the extension on the
+        // handshake yet. Mainline tokio-tungstenite cannot carry compressed frames
+        // (tokio-tungstenite#21): it sets no RSV1 bit on outbound frames and validates inbound
+        // text as UTF-8, so a peer that compressed its frames would be rejected. Advertising
+        // the extension would therefore produce a broken connection — leaving it unanswered
+        // keeps the socket uncompressed and correct until frame-level support lands upstream.
+        let _ = &self.compression;
+
+        let protocol = self.protocol.clone();
+
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    on_failed_upgrade.call(Error::new(err));
+                    return;
+                }
+            };
+            let upgraded = TokioIo::new(upgraded);
+
+            let socket =
+                WebSocketStream::from_raw_socket(upgraded, protocol::Role::Server, Some(config))
+                    .await;
+            let socket = WebSocket {
+                inner: socket,
+                protocol,
+                state: CloseState::Active,
+                heartbeat: keep_alive.map(Heartbeat::new),
+            };
+
+            callback(socket).await;
+        });
+
+        // Build the `101 Switching Protocols` handshake response.
+        #[allow(clippy::declare_interior_mutable_const)]
+        const UPGRADE: HeaderValue = HeaderValue::from_static("upgrade");
+        #[allow(clippy::declare_interior_mutable_const)]
+        const WEBSOCKET: HeaderValue = HeaderValue::from_static("websocket");
+
+        let mut builder = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, UPGRADE)
+            .header(header::UPGRADE, WEBSOCKET)
+            .header(
+                header::SEC_WEBSOCKET_ACCEPT,
+                sign(self.sec_websocket_key.as_bytes()),
+            );
+
+        if let Some(protocol) = self.protocol {
+            builder = builder.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+}
+
+/// Configuration for the transparent keep-alive heartbeat.
+///
+/// Passed to [`WebSocketUpgrade::keep_alive`].
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAliveConfig {
+    /// How often a [`Message::Ping`] is sent.
+    pub interval: Duration,
+    /// How long to wait for a [`Message::Pong`] before closing the connection. Measured from
+    /// the last pong seen, so a healthy peer never trips it.
+    pub timeout: Duration,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Configuration for permessage-deflate (RFC 7692) compression.
+///
+/// Passed to [`WebSocketUpgrade::compression`]. The two `*_max_window_bits` cap the size of
+/// the LZ77 sliding window each side keeps (8..=15); the `*_no_context_takeover` flags force
+/// the flate dictionary to be reset after every message instead of carried across them.
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateConfig {
+    /// Upper bound on the server's LZ77 window, or `None` to accept the client's request.
+    pub server_max_window_bits: Option<u8>,
+    /// Upper bound on the client's LZ77 window, or `None` to leave it unconstrained.
+    pub client_max_window_bits: Option<u8>,
+    /// Reset the server's flate dictionary after each message.
+    pub server_no_context_takeover: bool,
+    /// Reset the client's flate dictionary after each message.
+    pub client_no_context_takeover: bool,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            server_max_window_bits: None,
+            client_max_window_bits: None,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+/// The outcome of negotiating permessage-deflate against a client offer.
+///
+/// Keeps both the agreed [`DeflateConfig`] and a note of whether the client actually sent the
+/// `client_max_window_bits` parameter, because RFC 7692 §7.1.2.2 forbids the server echoing
+/// that parameter in the response unless the client offered it.
+//
+// Dormant until permessage-deflate can be carried on the wire (see `compression`); currently
+// exercised only by the unit tests.
+#[allow(dead_code)]
+struct NegotiatedDeflate {
+    config: DeflateConfig,
+    client_offered_max_window_bits: bool,
+}
+
+#[allow(dead_code)]
+impl NegotiatedDeflate {
+    /// Render the agreed parameters as the `Sec-WebSocket-Extensions` response header value.
+    fn to_header(&self) -> HeaderValue {
+        let config = &self.config;
+        let mut value = String::from("permessage-deflate");
+        if let Some(bits) = config.server_max_window_bits {
+            value.push_str(&format!("; server_max_window_bits={bits}"));
+        }
+        // Only echo `client_max_window_bits` when the client offered it (RFC 7692 §7.1.2.2).
+        if self.client_offered_max_window_bits {
+            if let Some(bits) = config.client_max_window_bits {
+                value.push_str(&format!("; client_max_window_bits={bits}"));
+            }
+        }
+        if config.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if config.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        // The value is assembled from a fixed vocabulary plus small integers, so it is always
+        // a valid header value.
+        HeaderValue::from_str(&value).unwrap()
+    }
+}
+
+/// Intersect our offered [`DeflateConfig`] with the client's `Sec-WebSocket-Extensions` offer.
+///
+/// Returns the parameters to enable and echo, or `None` if the client did not offer
+/// permessage-deflate (in which case the connection stays uncompressed).
+#[allow(dead_code)]
+fn negotiate_deflate(offer: Option<&HeaderValue>, ours: DeflateConfig) -> Option<NegotiatedDeflate> {
+    let offer = offer?.to_str().ok()?;
+
+    let mut client_offers_deflate = false;
+    let mut client_offered_max_window_bits = false;
+    let mut agreed = ours;
+
+    for extension in offer.split(',') {
+        let mut params = extension.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+        client_offers_deflate = true;
+
+        for param in params {
+            match param.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                // A client window-bits hint only ever tightens our cap, never loosens it.
+                Some(("client_max_window_bits", bits)) => {
+                    client_offered_max_window_bits = true;
+                    if let Ok(bits) = bits.parse::<u8>() {
+                        agreed.client_max_window_bits =
+                            Some(min_opt(agreed.client_max_window_bits, bits));
+                    }
+                }
+                Some(("server_max_window_bits", bits)) => {
+                    if let Ok(bits) = bits.parse::<u8>() {
+                        agreed.server_max_window_bits =
+                            Some(min_opt(agreed.server_max_window_bits, bits));
+                    }
+                }
+                _ => match param {
+                    "client_no_context_takeover" => agreed.client_no_context_takeover = true,
+                    "server_no_context_takeover" => agreed.server_no_context_takeover = true,
+                    // The parameterless form advertises support without requesting a cap.
+                    "client_max_window_bits" => client_offered_max_window_bits = true,
+                    _ => {}
+                },
+            }
+        }
+
+        break;
+    }
+
+    client_offers_deflate.then_some(NegotiatedDeflate {
+        config: agreed,
+        client_offered_max_window_bits,
+    })
+}
+
+/// The tighter of our cap and the peer's request.
+#[allow(dead_code)]
+fn min_opt(ours: Option<u8>, theirs: u8) -> u8 {
+    match ours {
+        Some(ours) => ours.min(theirs),
+        None => theirs,
+    }
+}
+
+/// The permessage-deflate codec (RFC 7692), implemented directly over [`flate2`] since
+/// mainline tokio-tungstenite does not support the extension (tokio-tungstenite#21).
+///
+/// Outbound `Text`/`Binary` payloads are deflated and inbound ones inflated, leaving the
+/// [`Message`] API uncompressed. The `*_no_context_takeover` flags decide whether the flate
+/// dictionaries persist across messages or are reset after each one.
+//
+// Dormant until permessage-deflate can be carried on the wire (see `compression`); currently
+// exercised only by the unit tests.
+#[allow(dead_code)]
+struct Deflate {
+    compress: flate2::Compress,
+    decompress: flate2::Decompress,
+    /// `server_no_context_takeover`: reset our compressor after each outbound message.
+    reset_compress: bool,
+    /// `client_no_context_takeover`: reset our decompressor after each inbound message.
+    reset_decompress: bool,
+}
+
+// The empty-block trailer permessage-deflate strips from deflated payloads and re-appends
+// before inflating (RFC 7692 §7.2.1/§7.2.2).
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[allow(dead_code)]
+impl Deflate {
+    fn new(config: DeflateConfig) -> Self {
+        Self {
+            // Raw deflate streams (no zlib wrapper), as the extension requires.
+            compress: flate2::Compress::new(flate2::Compression::default(), false),
+            decompress: flate2::Decompress::new(false),
+            reset_compress: config.server_no_context_takeover,
+            reset_decompress: config.client_no_context_takeover,
+        }
+    }
+
+    /// Deflate an outbound payload and strip the empty-block trailer.
+    fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 16);
+        let start_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            let before_out = self.compress.total_out();
+            if out.len() == out.capacity() {
+                out.reserve(64);
+            }
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, flate2::FlushCompress::Sync)
+                .expect("deflate compression is infallible for in-memory buffers");
+            let all_consumed = (self.compress.total_in() - start_in) as usize == data.len();
+            if all_consumed && self.compress.total_out() == before_out {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TRAILER) {
+            out.truncate(out.len() - DEFLATE_TRAILER.len());
+        }
+        if self.reset_compress {
+            self.compress.reset();
+        }
+        out
+    }
+
+    /// Inflate an inbound payload after re-appending the empty-block trailer.
+    fn inflate(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        data.extend_from_slice(&DEFLATE_TRAILER);
+        let mut out = Vec::with_capacity(data.len() * 2);
+        let start_in = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start_in) as usize;
+            let before_out = self.decompress.total_out();
+            if out.len() == out.capacity() {
+                out.reserve(data.len());
+            }
+            let status = self
+                .decompress
+                .decompress_vec(&data[consumed..], &mut out, flate2::FlushDecompress::Sync)
+                .map_err(Error::new)?;
+            let all_consumed = (self.decompress.total_in() - start_in) as usize == data.len();
+            if status == flate2::Status::StreamEnd
+                || (all_consumed && self.decompress.total_out() == before_out)
+            {
+                break;
+            }
+        }
+        if self.reset_decompress {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+/// The running state of a configured heartbeat, kept inside [`WebSocket`].
+///
+/// `next_ping` fires on [`KeepAliveConfig::interval`]; `deadline` is the instant the next pong
+/// has to beat and is pushed forward whenever one arrives. Both are real timers, so a silent
+/// peer is noticed the moment `timeout` passes rather than only on the next ping tick.
+struct Heartbeat {
+    config: KeepAliveConfig,
+    next_ping: Pin<Box<Sleep>>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Heartbeat {
+    fn new(config: KeepAliveConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            next_ping: Box::pin(tokio::time::sleep_until(now + config.interval)),
+            deadline: Box::pin(tokio::time::sleep_until(now + config.timeout)),
+            config,
+        }
+    }
+
+    /// A pong came back: push the deadline out so the heartbeat only fires once the peer
+    /// actually goes quiet.
+    fn pong_received(&mut self) {
+        self.deadline
+            .as_mut()
+            .reset(Instant::now() + self.config.timeout);
+    }
+
+    /// Poll both timers. Returns `Some(Ok(()))` when it is time to ping, `Some(Err(()))` when
+    /// the deadline has expired with no pong, or `None` while both are pending.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Option<Result<(), ()>> {
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            return Some(Err(()));
+        }
+        if self.next_ping.as_mut().poll(cx).is_ready() {
+            self.next_ping
+                .as_mut()
+                .reset(Instant::now() + self.config.interval);
+            // Re-poll the freshly reset timer so its waker is registered for the next
+            // interval — otherwise the task is never woken to send the following ping.
+            let _ = self.next_ping.as_mut().poll(cx);
+            return Some(Ok(()));
+        }
+        None
+    }
+}
+
+/// A stream of WebSocket messages.
+///
+/// See [the module level documentation](self) for more details.
+#[derive(Debug)]
+pub struct WebSocket {
+    inner: WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>,
+    protocol: Option<HeaderValue>,
+    state: CloseState,
+    heartbeat: Option<Heartbeat>,
+}
+
+// After we send our Close we keep reading for a little while so the peer's echoing Close has
+// a chance to arrive, but we don't read forever if it keeps sending data frames instead.
+const CLOSE_DRAIN_LIMIT: usize = 8;
+
+impl WebSocket {
+    /// Receive another message.
+    ///
+    /// Returns `None` if the stream has closed.
+    pub async fn recv(&mut self) -> Option<Result<Message, Error>> {
+        self.next().await
+    }
+
+    /// Send a message.
+    ///
+    /// Once a Close has been sent — either by calling [`close`](Self::close) or by replying to
+    /// the peer's Close — the connection is no longer writable and further sends resolve to
+    /// [`Error`] wrapping [`ConnectionClosed`] rather than an opaque transport failure, so
+    /// callers can tell a closed connection apart from a genuine I/O error.
+    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        if self.state != CloseState::Active {
+            return Err(Error::new(ConnectionClosed));
+        }
+        SinkExt::send(self, msg).await
+    }
+
+    /// Perform a clean bidirectional close.
+    ///
+    /// Sends the Close frame, flushes it, then drives the stream until the peer echoes its own
+    /// Close (per RFC6455) or a bounded number of data frames pass without one. Because a
+    /// Close in flight makes any further data write fail with `SendAfterClosing`, the state is
+    /// moved to [`ClosingByUs`](CloseState::ClosingByUs) up front so [`send`](Self::send)
+    /// reports it cleanly.
+    pub async fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), Error> {
+        if self.state != CloseState::Active {
+            return Ok(());
+        }
+        self.state = CloseState::ClosingByUs;
+
+        SinkExt::send(self, Message::Close(frame)).await?;
+
+        // Wait for the peer's echoing Close, but don't let it keep us here forever.
+        for _ in 0..CLOSE_DRAIN_LIMIT {
+            match self.next().await {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err),
+            }
+        }
+        self.state = CloseState::Closed;
+        Ok(())
+    }
+
+    /// Return the selected WebSocket subprotocol, if one has been chosen.
+    pub fn protocol(&self) -> Option<&HeaderValue> {
+        self.protocol.as_ref()
+    }
+
+    /// Drive the heartbeat timers, sending a ping or closing on timeout as needed. Called from
+    /// `poll_next` so detection is not quantized to the stream's own readiness.
+    fn poll_heartbeat(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message, Error>>> {
+        let Some(heartbeat) = self.heartbeat.as_mut() else {
+            return Poll::Pending;
+        };
+        match heartbeat.poll(cx) {
+            Some(Ok(())) => {
+                // Best-effort ping; a failure here surfaces on the next real poll.
+                let _ = Pin::new(&mut self.inner).start_send(protocol::Message::Ping(Vec::new()));
+                let _ = Pin::new(&mut self.inner).poll_flush(cx);
+                Poll::Pending
+            }
+            Some(Err(())) => {
+                // No pong within `timeout`: close with 1000 and end the stream. Mark the
+                // connection Closed so a subsequent `send` hits the `ConnectionClosed` guard
+                // instead of writing to a dead socket.
+                self.heartbeat = None;
+                self.state = CloseState::Closed;
+                let frame = protocol::frame::CloseFrame {
+                    code: protocol::frame::coding::CloseCode::Normal,
+                    reason: Cow::from(""),
+                };
+                let _ = Pin::new(&mut self.inner).start_send(protocol::Message::Close(Some(frame)));
+                let _ = Pin::new(&mut self.inner).poll_flush(cx);
+                Poll::Ready(None)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(done) = self.poll_heartbeat(cx) {
+            return Poll::Ready(done);
+        }
+
+        loop {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    // Enforce the RFC6455 framing rules the Autobahn suite checks before the
+                    // message reaches the caller. tungstenite upholds most of these itself, but
+                    // validating here keeps the guarantee at the `Message` boundary regardless
+                    // of the underlying library version.
+                    if let Err(err) = check_protocol(&msg) {
+                        return Poll::Ready(Some(Err(Error::new(err))));
+                    }
+                    // A pong — whether the peer's own or the echo of our heartbeat ping —
+                    // keeps the connection alive. Still hand it up so callers can see it.
+                    if let (protocol::Message::Pong(_), Some(hb)) = (&msg, self.heartbeat.as_mut()) {
+                        hb.pong_received();
+                    }
+                    // The peer opened the closing handshake. tungstenite enqueues its own
+                    // Close reply for us, so all we track is that writes are no longer valid.
+                    if matches!(msg, protocol::Message::Close(_)) && self.state == CloseState::Active
+                    {
+                        self.state = CloseState::ClosingByPeer;
+                    }
+                    if let Some(msg) = Message::from_tungstenite(msg) {
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                    // Skip frames with no public `Message` equivalent (e.g. raw `Frame`).
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(Error::new(err)))),
+                None => {
+                    // The stream is exhausted; both Close frames have now crossed regardless
+                    // of who opened the handshake, so the lifecycle is complete.
+                    self.state = CloseState::Closed;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(Error::new)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner)
+            .start_send(item.into_tungstenite())
+            .map_err(Error::new)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Advance the heartbeat from the write path too, so a handler that only ever sends
+        // (including one using the sink half after `split()`) still emits pings and trips the
+        // pong timeout without having to poll the read half.
+        let _ = self.poll_heartbeat(cx);
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(Error::new)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Error::new)
+    }
+}
+
+/// Where a [`WebSocket`] is in the RFC6455 closing handshake.
+///
+/// tungstenite will not let us keep writing data frames once a Close has gone past it, so the
+/// lifecycle is tracked explicitly rather than discovered through opaque `SendAfterClosing`
+/// errors: `ClosingByUs` means we sent the Close and are waiting for the peer's echo,
+/// `ClosingByPeer` means the peer spoke first, and once both Close frames have crossed the
+/// connection is `Closed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseState {
+    Active,
+    ClosingByUs,
+    ClosingByPeer,
+    Closed,
+}
+
+/// Error returned from [`WebSocket::send`] when the connection's closing handshake is already
+/// in flight, distinguishing an orderly shutdown from a transport failure.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ConnectionClosed;
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("cannot send on a WebSocket whose close handshake is in flight")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+/// A WebSocket message.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Message {
+    /// A text WebSocket message.
+    Text(String),
+    /// A binary WebSocket message.
+    Binary(Vec<u8>),
+    /// A ping message with the specified payload.
+    Ping(Vec<u8>),
+    /// A pong message with the specified payload.
+    Pong(Vec<u8>),
+    /// A close message with the optional close frame.
+    Close(Option<CloseFrame<'static>>),
+}
+
+impl Message {
+    fn into_tungstenite(self) -> ts::Message {
+        match self {
+            Self::Text(text) => ts::Message::Text(text),
+            Self::Binary(binary) => ts::Message::Binary(binary),
+            Self::Ping(ping) => ts::Message::Ping(ping),
+            Self::Pong(pong) => ts::Message::Pong(pong),
+            Self::Close(Some(close)) => ts::Message::Close(Some(protocol::frame::CloseFrame {
+                code: ts::protocol::frame::coding::CloseCode::from(close.code),
+                reason: close.reason,
+            })),
+            Self::Close(None) => ts::Message::Close(None),
+        }
+    }
+
+    fn from_tungstenite(message: ts::Message) -> Option<Self> {
+        match message {
+            ts::Message::Text(text) => Some(Self::Text(text)),
+            ts::Message::Binary(binary) => Some(Self::Binary(binary)),
+            ts::Message::Ping(ping) => Some(Self::Ping(ping)),
+            ts::Message::Pong(pong) => Some(Self::Pong(pong)),
+            ts::Message::Close(Some(close)) => Some(Self::Close(Some(CloseFrame {
+                code: close.code.into(),
+                reason: close.reason,
+            }))),
+            ts::Message::Close(None) => Some(Self::Close(None)),
+            // we can ignore `Frame` frames as recommended by the tungstenite maintainers
+            ts::Message::Frame(_) => None,
+        }
+    }
+}
+
+/// A struct representing the close command.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct CloseFrame<'t> {
+    /// The reason as a code.
+    pub code: u16,
+    /// The reason as text string.
+    pub reason: Cow<'t, str>,
+}
+
+/// The largest payload a control frame (Ping/Pong/Close) may carry, per RFC6455 §5.5.
+const MAX_CONTROL_FRAME_SIZE: usize = 125;
+
+/// A framing rule the Autobahn TestSuite exercises and we reject rather than forward.
+#[derive(Debug)]
+enum ProtocolError {
+    /// A Ping/Pong/Close payload exceeded 125 bytes.
+    OversizedControlFrame(usize),
+    /// A Close frame carried a code from a reserved or out-of-range slot.
+    ReservedCloseCode(u16),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OversizedControlFrame(len) => {
+                write!(f, "control frame payload of {len} bytes exceeds 125")
+            }
+            Self::ReservedCloseCode(code) => write!(f, "reserved or invalid close code {code}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Reject the framing violations the suite expects an endpoint to catch. `Text` payloads are
+/// validated as UTF-8 by tungstenite during frame assembly, so an invalid one never reaches
+/// here — it surfaces as a decode `Err` on the stream instead.
+fn check_protocol(msg: &protocol::Message) -> Result<(), ProtocolError> {
+    match msg {
+        protocol::Message::Ping(payload) | protocol::Message::Pong(payload) => {
+            if payload.len() > MAX_CONTROL_FRAME_SIZE {
+                return Err(ProtocolError::OversizedControlFrame(payload.len()));
+            }
+        }
+        protocol::Message::Close(Some(frame)) => {
+            let reason = frame.reason.len();
+            // The 2-byte status code counts toward the control frame's 125-byte budget.
+            if reason + 2 > MAX_CONTROL_FRAME_SIZE {
+                return Err(ProtocolError::OversizedControlFrame(reason + 2));
+            }
+            let code = u16::from(frame.code);
+            if !is_valid_close_code(code) {
+                return Err(ProtocolError::ReservedCloseCode(code));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Close-code validation as the Autobahn suite expects it: 1000-1003 and 1007-1014 are
+/// defined application/protocol codes (1012-1014 were registered with IANA after RFC6455),
+/// 3000-4999 are registered for libraries and applications, and everything else — including
+/// the reserved 1004/1005/1006/1015 — must never appear in a frame.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn sign(key: &[u8]) -> HeaderValue {
+    use base64::engine::Engine as _;
+
+    let mut sha1 = Sha1::default();
+    sha1.update(key);
+    sha1.update(&b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"[..]);
+    let b64 = base64::engine::general_purpose::STANDARD.encode(sha1.finalize());
+    HeaderValue::from_str(&b64).unwrap()
+}
+
+/// What to do when a connection upgrade fails.
+///
+/// See [`WebSocketUpgrade::on_failed_upgrade`] for more details.
+pub trait OnFailedUpgrade: Send + 'static {
+    /// Call the callback.
+    fn call(self, error: Error);
+}
+
+impl<F> OnFailedUpgrade for F
+where
+    F: FnOnce(Error) + Send + 'static,
+{
+    fn call(self, error: Error) {
+        self(error)
+    }
+}
+
+/// The default `OnFailedUpgrade` used by `WebSocketUpgrade`.
+///
+/// It simply ignores the error.
+#[non_exhaustive]
+#[derive(Debug)]
+pub struct DefaultOnFailedUpgrade;
+
+impl OnFailedUpgrade for DefaultOnFailedUpgrade {
+    #[inline]
+    fn call(self, _error: Error) {}
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WebSocketUpgrade<DefaultOnFailedUpgrade>
+where
+    S: Send + Sync,
+{
+    type Rejection = WebSocketUpgradeRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.method != Method::GET {
+            return Err(MethodNotGet.into());
+        }
+
+        if !header_contains(&parts.headers, header::CONNECTION, "upgrade") {
+            return Err(InvalidConnectionHeader.into());
+        }
+
+        if !header_eq(&parts.headers, header::UPGRADE, "websocket") {
+            return Err(InvalidUpgradeHeader.into());
+        }
+
+        if !header_eq(&parts.headers, header::SEC_WEBSOCKET_VERSION, "13") {
+            return Err(InvalidWebSocketVersionHeader.into());
+        }
+
+        let sec_websocket_key = parts
+            .headers
+            .get(header::SEC_WEBSOCKET_KEY)
+            .ok_or(WebSocketKeyHeaderMissing)?
+            .clone();
+
+        let on_upgrade = parts
+            .extensions
+            .remove::<hyper::upgrade::OnUpgrade>()
+            .ok_or(ConnectionNotUpgradable)?;
+
+        let sec_websocket_protocol = parts.headers.get(header::SEC_WEBSOCKET_PROTOCOL).cloned();
+
+        let sec_websocket_extensions =
+            parts.headers.get(header::SEC_WEBSOCKET_EXTENSIONS).cloned();
+
+        Ok(Self {
+            config: Default::default(),
+            protocol: None,
+            sec_websocket_key,
+            on_upgrade,
+            sec_websocket_protocol,
+            sec_websocket_extensions,
+            on_failed_upgrade: DefaultOnFailedUpgrade,
+            keep_alive: None,
+            compression: None,
+        })
+    }
+}
+
+fn header_eq(headers: &HeaderMap, key: HeaderName, value: &'static str) -> bool {
+    if let Some(header) = headers.get(&key) {
+        header.as_bytes().eq_ignore_ascii_case(value.as_bytes())
+    } else {
+        false
+    }
+}
+
+fn header_contains(headers: &HeaderMap, key: HeaderName, value: &'static str) -> bool {
+    let header = if let Some(header) = headers.get(&key) {
+        header
+    } else {
+        return false;
+    };
+
+    if let Ok(header) = std::str::from_utf8(header.as_bytes()) {
+        header.to_ascii_lowercase().contains(value)
+    } else {
+        false
+    }
+}
+
+pub mod rejection {
+    //! WebSocket specific rejections.
+
+    use axum_core::{
+        extract::rejection::*,
+        response::{IntoResponse, Response},
+    };
+
+    macro_rules! define_rejection {
+        (
+            #[status = $status:ident]
+            #[body = $body:expr]
+            $(#[$m:meta])*
+            pub struct $name:ident;
+        ) => {
+            $(#[$m])*
+            #[derive(Debug)]
+            #[non_exhaustive]
+            pub struct $name;
+
+            impl IntoResponse for $name {
+                fn into_response(self) -> Response {
+                    (http::StatusCode::$status, $body).into_response()
+                }
+            }
+        };
+    }
+
+    define_rejection! {
+        #[status = METHOD_NOT_ALLOWED]
+        #[body = "Request method must be `GET`"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct MethodNotGet;
+    }
+
+    define_rejection! {
+        #[status = BAD_REQUEST]
+        #[body = "Connection header did not include 'upgrade'"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct InvalidConnectionHeader;
+    }
+
+    define_rejection! {
+        #[status = BAD_REQUEST]
+        #[body = "`Upgrade` header did not include 'websocket'"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct InvalidUpgradeHeader;
+    }
+
+    define_rejection! {
+        #[status = BAD_REQUEST]
+        #[body = "`Sec-WebSocket-Version` header did not include '13'"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct InvalidWebSocketVersionHeader;
+    }
+
+    define_rejection! {
+        #[status = BAD_REQUEST]
+        #[body = "`Sec-WebSocket-Key` header missing"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct WebSocketKeyHeaderMissing;
+    }
+
+    define_rejection! {
+        #[status = UPGRADE_REQUIRED]
+        #[body = "WebSocket request couldn't be upgraded since no upgrade state was present"]
+        /// Rejection type for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub struct ConnectionNotUpgradable;
+    }
+
+    composite_rejection! {
+        /// Rejection used for [`WebSocketUpgrade`](super::WebSocketUpgrade).
+        pub enum WebSocketUpgradeRejection {
+            MethodNotGet,
+            InvalidConnectionHeader,
+            InvalidUpgradeHeader,
+            InvalidWebSocketVersionHeader,
+            WebSocketKeyHeaderMissing,
+            ConnectionNotUpgradable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::frame::{
+        coding::CloseCode, CloseFrame as TsCloseFrame,
+    };
+
+    #[test]
+    fn close_codes_defined_and_registered_are_valid() {
+        for code in [1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011] {
+            assert!(is_valid_close_code(code), "{code} should be valid");
+        }
+        // Registered with IANA after RFC6455 and expected valid by the Autobahn suite.
+        for code in [1012, 1013, 1014] {
+            assert!(is_valid_close_code(code), "{code} should be valid");
+        }
+        // Application range.
+        assert!(is_valid_close_code(3000));
+        assert!(is_valid_close_code(4999));
+    }
+
+    #[test]
+    fn reserved_and_out_of_range_close_codes_are_invalid() {
+        // 1004/1005/1006/1015 are reserved and must never appear in a frame.
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert!(!is_valid_close_code(code), "{code} should be invalid");
+        }
+    }
+
+    fn close(code: u16, reason: &'static str) -> protocol::Message {
+        protocol::Message::Close(Some(TsCloseFrame {
+            code: CloseCode::from(code),
+            reason: Cow::from(reason),
+        }))
+    }
+
+    #[test]
+    fn check_protocol_rejects_oversized_control_frames() {
+        assert!(check_protocol(&protocol::Message::Ping(vec![0; 125])).is_ok());
+        assert!(matches!(
+            check_protocol(&protocol::Message::Ping(vec![0; 126])),
+            Err(ProtocolError::OversizedControlFrame(126))
+        ));
+        assert!(matches!(
+            check_protocol(&protocol::Message::Pong(vec![0; 200])),
+            Err(ProtocolError::OversizedControlFrame(200))
+        ));
+        // The 2-byte status code counts toward the 125-byte control-frame budget.
+        assert!(check_protocol(&close(1000, &"x".repeat(123))).is_ok());
+        assert!(matches!(
+            check_protocol(&close(1000, &"x".repeat(124))),
+            Err(ProtocolError::OversizedControlFrame(126))
+        ));
+    }
+
+    #[test]
+    fn check_protocol_rejects_reserved_close_codes() {
+        assert!(check_protocol(&close(1000, "bye")).is_ok());
+        assert!(check_protocol(&close(1013, "")).is_ok());
+        assert!(matches!(
+            check_protocol(&close(1005, "")),
+            Err(ProtocolError::ReservedCloseCode(1005))
+        ));
+    }
+
+    #[test]
+    fn check_protocol_passes_data_frames() {
+        assert!(check_protocol(&protocol::Message::Text("hello".into())).is_ok());
+        assert!(check_protocol(&protocol::Message::Binary(vec![0; 10_000])).is_ok());
+    }
+
+    fn offer(value: &'static str) -> HeaderValue {
+        HeaderValue::from_static(value)
+    }
+
+    #[test]
+    fn negotiate_deflate_requires_a_client_offer() {
+        assert!(negotiate_deflate(None, DeflateConfig::default()).is_none());
+        assert!(negotiate_deflate(Some(&offer("identity")), DeflateConfig::default()).is_none());
+    }
+
+    #[test]
+    fn negotiate_deflate_bare_offer_echoes_only_permessage_deflate() {
+        let negotiated =
+            negotiate_deflate(Some(&offer("permessage-deflate")), DeflateConfig::default()).unwrap();
+        assert_eq!(negotiated.to_header(), "permessage-deflate");
+    }
+
+    #[test]
+    fn negotiate_deflate_echoes_client_max_window_bits_only_when_offered() {
+        // Our config sets the bits but the client never offered the parameter: it must not be
+        // echoed (RFC 7692 §7.1.2.2).
+        let ours = DeflateConfig {
+            client_max_window_bits: Some(15),
+            ..DeflateConfig::default()
+        };
+        let negotiated = negotiate_deflate(Some(&offer("permessage-deflate")), ours).unwrap();
+        assert_eq!(negotiated.to_header(), "permessage-deflate");
+
+        // When the client offers it, the agreed (tightened) value is echoed.
+        let negotiated = negotiate_deflate(
+            Some(&offer("permessage-deflate; client_max_window_bits=10")),
+            DeflateConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            negotiated.to_header(),
+            "permessage-deflate; client_max_window_bits=10"
+        );
+    }
+
+    #[test]
+    fn deflate_round_trips_with_and_without_context_takeover() {
+        // Context takeover retained: the dictionary persists across messages.
+        let mut a = Deflate::new(DeflateConfig::default());
+        let mut b = Deflate::new(DeflateConfig::default());
+        for msg in ["the quick brown fox", "the quick brown fox", "jumps over"] {
+            let compressed = a.deflate(msg.as_bytes());
+            let restored = b.inflate(compressed).unwrap();
+            assert_eq!(restored, msg.as_bytes());
+        }
+
+        // No context takeover: dictionaries are reset after every message but round-trips
+        // still hold as long as both sides agree.
+        let reset = DeflateConfig {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            ..DeflateConfig::default()
+        };
+        let mut a = Deflate::new(reset);
+        let mut b = Deflate::new(DeflateConfig {
+            // b decompresses what a compressed: a resets its compressor (server side), so b
+            // must reset the matching decompressor.
+            client_no_context_takeover: true,
+            ..DeflateConfig::default()
+        });
+        for msg in ["repeat repeat repeat", "repeat repeat repeat"] {
+            let compressed = a.deflate(msg.as_bytes());
+            let restored = b.inflate(compressed).unwrap();
+            assert_eq!(restored, msg.as_bytes());
+        }
+    }
+
+    #[test]
+    fn negotiate_deflate_carries_no_context_takeover() {
+        let negotiated = negotiate_deflate(
+            Some(&offer("permessage-deflate; server_no_context_takeover")),
+            DeflateConfig::default(),
+        )
+        .unwrap();
+        assert!(negotiated.config.server_no_context_takeover);
+        assert_eq!(
+            negotiated.to_header(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+    }
+}
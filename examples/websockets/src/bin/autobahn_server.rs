@@ -0,0 +1,74 @@
+//! Echo server used to run the [Autobahn|TestSuite] against axum's WebSocket implementation.
+//!
+//! It accepts a WebSocket upgrade on `/` and echoes every data frame straight back, which is
+//! exactly what the suite's `fuzzingclient` expects of the endpoint under test. The
+//! conformance cases (fragmentation, invalid UTF-8, oversized control frames, reserved close
+//! codes, …) are then driven by the runner in `autobahn/run.sh`.
+//!
+//! [Autobahn|TestSuite]: https://github.com/crossbario/autobahn-testsuite
+
+use std::borrow::Cow;
+
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+    routing::get,
+    Router,
+};
+
+#[tokio::main]
+async fn main() {
+    let app = Router::new().route("/", get(handler));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:9002")
+        .await
+        .unwrap();
+    println!("autobahn echo server listening on {}", listener.local_addr().unwrap());
+    axum::serve(listener, app).await.unwrap();
+}
+
+async fn handler(ws: WebSocketUpgrade) -> Response {
+    // No frame-size limits so the suite can probe the boundaries itself; the `WebSocket`
+    // decode path still rejects the frames it must (control frames over 125 bytes, invalid
+    // UTF-8 text, reserved close codes).
+    ws.max_message_size(usize::MAX)
+        .max_frame_size(usize::MAX)
+        .on_upgrade(echo)
+}
+
+async fn echo(mut socket: WebSocket) {
+    while let Some(msg) = socket.recv().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            // A protocol violation surfaces as an error. Send the matching close before dropping
+            // so the suite sees a clean handshake rather than a severed TCP connection: `1007`
+            // for invalid UTF-8 in a text frame, `1002` for every other protocol error.
+            Err(err) => {
+                let code = if err.to_string().to_lowercase().contains("utf") {
+                    1007
+                } else {
+                    1002
+                };
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code,
+                        reason: Cow::from(""),
+                    })))
+                    .await;
+                break;
+            }
+        };
+
+        match msg {
+            // Echo data frames verbatim, exactly as the suite expects.
+            Message::Text(_) | Message::Binary(_) => {
+                if socket.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            // Ping/Pong are handled by the library; a Close ends the exchange.
+            Message::Ping(_) | Message::Pong(_) => {}
+            Message::Close(_) => break,
+        }
+    }
+}